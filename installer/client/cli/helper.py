@@ -1,5 +1,6 @@
 import os
 import sys
+from datetime import datetime
 
 
 class Session:
@@ -9,6 +10,10 @@ class Session:
         self.sessions_folder = os.path.join(config_folder, "sessions")
         if not os.path.exists(self.sessions_folder):
             os.makedirs(self.sessions_folder)
+        # Kept outside sessions_folder so unfinished responses never show up as sessions
+        self.partial_folder = os.path.join(config_folder, "partial")
+        if not os.path.exists(self.partial_folder):
+            os.makedirs(self.partial_folder)
 
     def find_most_recent_file(self):
         # Ensure the directory exists
@@ -37,6 +42,58 @@ class Session:
             f.write(f"{user}\n")
             f.write(f"{response}\n")
 
+    def partial_file(self, session):
+        return os.path.join(self.partial_folder, f"{session}.partial")
+
+    def open_partial(self, session):
+        """Open a fresh .partial file for the response being streamed into a session.
+
+        A file left behind by an earlier run is set aside first, never overwritten.
+        """
+        self.set_aside_partial(session)
+        return open(self.partial_file(session), "w")
+
+    def set_aside_partial(self, session):
+        """Rename a leftover .partial file to a timestamped name and return its new path."""
+        file = self.partial_file(session)
+        if not os.path.exists(file):
+            return None
+        timestamp = datetime.now().strftime("%Y%m%d%H%M%S")
+        aside = f"{file}.{timestamp}"
+        count = 1
+        while os.path.exists(aside):
+            aside = f"{file}.{timestamp}-{count}"
+            count += 1
+        os.rename(file, aside)
+        return aside
+
+    def clear_partial(self, session):
+        file = self.partial_file(session)
+        if os.path.exists(file):
+            os.remove(file)
+
+    def recover_partial(self, session):
+        """Offer to save an unfinished streamed response left behind by a crashed run.
+
+        If the offer can't be made (stdin is piped) or is declined, the response is kept
+        under a timestamped name instead.
+        """
+        file = self.partial_file(session)
+        if not os.path.exists(file):
+            return
+        if sys.stdin.isatty():
+            answer = input(
+                f"Found an unfinished response for session {session}. Recover it into the session? [y/N] ")
+            if answer.strip().lower() in ["y", "yes"]:
+                with open(file, "r") as f:
+                    self.save_to_session("", "", f.read(), session)
+                os.remove(file)
+                print(f"Recovered unfinished response into session {session}")
+                return
+        aside = self.set_aside_partial(session)
+        print(
+            f"Found an unfinished response for session {session} and kept it in {aside}", file=sys.stderr)
+
     def read_from_session(self, filename):
         file = os.path.join(self.sessions_folder, filename)
         if not os.path.exists(file):
@@ -2,11 +2,24 @@ import requests
 import os
 from openai import OpenAI, APIConnectionError
 import asyncio
+import base64
+import copy
+import fnmatch
+import hashlib
+import io
+import json
+import mimetypes
+import re
+from contextlib import contextmanager, redirect_stdout
+from functools import lru_cache
+from datetime import date
 import pyperclip
 import sys
+import time
 import platform
 from dotenv import load_dotenv
 import zipfile
+import tarfile
 import tempfile
 import subprocess
 import shutil
@@ -15,6 +28,12 @@ from youtube_transcript_api import YouTubeTranscriptApi
 current_directory = os.path.dirname(os.path.realpath(__file__))
 config_directory = os.path.expanduser("~/.config/fabric")
 env_file = os.path.join(config_directory, ".env")
+last_requests_file = os.path.join(config_directory, "last_requests.json")
+overrides_directory = os.path.join(config_directory, "overrides")
+registries_directory = os.path.join(config_directory, "registries")
+strategies_directory = os.path.join(config_directory, "strategies")
+# A few core patterns shipped with fabric, used until --update has downloaded the full set
+builtin_patterns_directory = os.path.join(current_directory, "builtin_patterns")
 
 
 class Standalone:
@@ -38,38 +57,615 @@ class Standalone:
         if args is None:
             args = type('Args', (), {})()
         env_file = os.path.expanduser(env_file)
+        self.env_file = env_file
         self.client = None
-        load_dotenv(env_file)
+        load_env_files(getattr(args, 'env_file', None), env_file)
         if "OPENAI_API_KEY" in os.environ:
             api_key = os.environ['OPENAI_API_KEY']
-            self.client = OpenAI(api_key=api_key)
+            self.client = OpenAI(api_key=api_key, **client_options(args))
+        self.compatible_clients = {}
+        for provider in compatible_providers():
+            client = compatible_client(provider, args)
+            if client:
+                self.compatible_clients[provider["name"]] = client
         self.local = False
         self.config_pattern_directory = config_directory
         self.pattern = pattern
+        # Set when the request fails, as errors are printed rather than raised
+        self.failed = False
+        self.attachments = load_attachments(getattr(args, 'attach', None))
+        # Defaults from the pattern's frontmatter, which the command line overrides
+        self.pattern_metadata = pattern_metadata(pattern) if pattern else {}
+        if hasattr(args, 'temp') and args.temp is None:
+            # On a copy, so one pattern's frontmatter doesn't set the temperature of the next in a --chain
+            args = copy.copy(args)
+            args.temp = self.pattern_metadata.get("temperature", 0)
         self.args = args
-        self.model = getattr(args, 'model', None)
+        self.strategy = load_strategy(args.strategy) if getattr(args, 'strategy', None) else ""
+        self.model = getattr(args, 'model', None) or self.pattern_metadata.get("model")
         if not self.model:
             self.model = os.environ.get('DEFAULT_MODEL', None)
             if not self.model:
                 self.model = 'gpt-4-turbo-preview'
         self.claude = False
-        sorted_gpt_models, ollamaList, claudeList, googleList = self.fetch_available_models()
+        if getattr(args, 'prompt_only', False):
+            # Nothing is sent, so don't ask the providers for their models either
+            sorted_gpt_models, ollamaList, claudeList, googleList, compatibleModels = [], [], [], [], {}
+        else:
+            sorted_gpt_models, ollamaList, claudeList, googleList, compatibleModels = self.fetch_available_models()
         self.sorted_gpt_models = sorted_gpt_models
         self.ollamaList = ollamaList
         self.claudeList = claudeList
         self.googleList = googleList
+        self.compatibleModels = compatibleModels
         self.local = self.model in ollamaList
         self.claude = self.model in claudeList
         self.google = self.model in googleList
+        # The name of the OpenAI-compatible provider serving the model, if it isn't OpenAI itself
+        self.compatible = next(
+            (name for name, models in compatibleModels.items() if self.model in models), None)
+
+    def backend(self):
+        """Return the name of the backend the selected model is sent to."""
+        if self.local:
+            return "ollama"
+        if self.claude:
+            return "claude"
+        if self.google:
+            return "google"
+        if self.compatible:
+            return self.compatible
+        return "openai"
+
+    def chat_client(self):
+        """Return the OpenAI-compatible client for the selected model, or exit if no provider offers it."""
+        client = self.compatible_clients[self.compatible] if self.compatible else self.client
+        if client is None or self.backend() in disabled_providers(self.args):
+            self.no_provider_error()
+        return client
+
+    def no_provider_error(self):
+        """Exit with an error naming every provider that was tried and how many models it offers."""
+        offered = {
+            "openai": len(self.sorted_gpt_models),
+            "ollama": len(self.ollamaList),
+            "claude": len(self.claudeList),
+            "google": len(self.googleList),
+        }
+        for name, models in self.compatibleModels.items():
+            offered[name] = len(models)
+        tried = []
+        for provider in providers(getattr(self.args, 'remoteOllamaServer', None), self.args):
+            if provider["disabled"]:
+                tried.append(f"{provider['name']} (disabled)")
+            elif provider["needs_key"] and not provider["configured"]:
+                tried.append(f"{provider['name']} (no key)")
+            else:
+                tried.append(
+                    f"{provider['name']} ({offered.get(provider['name'], 0)} models)")
+        print(
+            f"Error: no provider offers the model {self.model}. Tried {', '.join(tried)}. Run fabric --listmodels to see the available models.", file=sys.stderr)
+        sys.exit(1)
+
+    def format_citations(self, citations):
+        """Format the sources returned by Perplexity as a footnote list."""
+        return "\n\n" + "\n".join(f"[{i}] {url}" for i, url in enumerate(citations, 1))
+
+    def show_config(self):
+        """Print the effective configuration to stderr. API keys are never printed."""
+        config = {
+            "model": self.model,
+            "backend": self.backend(),
+            "temperature": getattr(self.args, 'temp', None),
+            "top_p": getattr(self.args, 'top_p', None),
+            "top_k": getattr(self.args, 'top_k', None) if getattr(self.args, 'top_k', None) is not None else "(model default)",
+            "stop sequences": ", ".join(repr(stop) for stop in getattr(self.args, 'stop', None) or []) or "(none)",
+            "seed": getattr(self.args, 'seed', None) if getattr(self.args, 'seed', None) is not None else "(none)",
+            "frequency_penalty": getattr(self.args, 'frequency_penalty', None),
+            "presence_penalty": getattr(self.args, 'presence_penalty', None),
+            "timeout": getattr(self.args, 'timeout', None) or "(none)",
+            "retries": getattr(self.args, 'retries', None) if getattr(self.args, 'retries', None) is not None else "(SDK default)",
+            "pattern": self.pattern or "(none)",
+            "session": getattr(self.args, 'session', None) or "(none)",
+            "allowed models": os.environ.get("ALLOWED_MODELS") or "(all)",
+            "env files": ", ".join(env_files(getattr(self.args, 'env_file', None), self.env_file)) or "(none)",
+        }
+        for directory in pattern_directories():
+            config[f"patterns directory {directory['path']}"] = f"{directory['count']} patterns"
+        for registry in pattern_registries():
+            config[f"pattern registry {registry.url}"] = f"{len(registry.patterns())} patterns"
+        for provider in providers(getattr(self.args, 'remoteOllamaServer', None), self.args):
+            config[f"provider {provider['name']}"] = f"{provider['base_url']} ({provider_status(provider)})"
+        for name, value in config.items():
+            print(f"{name}: {value}", file=sys.stderr)
+
+    def pattern_file(self):
+        """Return the pattern's system.md, logging to stderr when a local override replaces it."""
+        path = pattern_system_path(self.pattern)
+        if self.pattern and path.startswith(overrides_directory):
+            print(f"Using the local override {path}", file=sys.stderr)
+        return path
+
+    def ensure_model_allowed(self):
+        """Exit with an error when ALLOWED_MODELS is set and doesn't match the selected model."""
+        if model_allowed(self.model):
+            return
+        print(
+            f"Error: the model {self.model} is not allowed by ALLOWED_MODELS ({os.environ['ALLOWED_MODELS']})", file=sys.stderr)
+        sys.exit(1)
+
+    def request_hash(self, messages):
+        """Hash everything that is sent to the model: the model, the messages and the sampling parameters."""
+        request = {
+            "model": self.model,
+            "messages": messages,
+            "attachments": [data for _, data, _ in self.attachments],
+            "params": {name: getattr(self.args, name, None) for name in ["temp", "top_p", "top_k", "stop", "seed", "json_schema", "frequency_penalty", "presence_penalty"]},
+        }
+        return hashlib.sha256(json.dumps(request, sort_keys=True).encode("utf-8")).hexdigest()
+
+    def request_key(self):
+        """Name the session and pattern whose last request --only-on-change compares against."""
+        return f"{getattr(self.args, 'session', None) or ''}/{self.pattern or ''}"
+
+    def unchanged_since_last_run(self, messages):
+        """With --only-on-change, tell whether this request is identical to the last one for the same session and pattern."""
+        if not getattr(self.args, 'only_on_change', False) or getattr(self.args, 'force', False):
+            return False
+        return read_last_requests().get(self.request_key()) == self.request_hash(messages)
+
+    def remember_request(self, messages):
+        """Record a completed request for --only-on-change."""
+        if not getattr(self.args, 'only_on_change', False):
+            return
+        last_requests = read_last_requests()
+        last_requests[self.request_key()] = self.request_hash(messages)
+        with open(last_requests_file, "w") as f:
+            json.dump(last_requests, f, indent=2)
+
+    def print_prompt(self, messages):
+        """Print the assembled messages as the model would read them, without sending them."""
+        for message in messages:
+            print(f"{message['role'].upper()}:")
+            print(message['content'])
+            print()
+        if self.attachments:
+            print("ATTACHMENTS:")
+            for _, _, path in self.attachments:
+                print(path)
+            print()
+
+    def anthropic_batches(self, method, url="https://api.anthropic.com/v1/messages/batches", **kwargs):
+        """Call Anthropic's Message Batches API and return the response, or exit with the API's error."""
+        response = requests.request(
+            method, url,
+            headers={"x-api-key": os.environ["CLAUDE_API_KEY"],
+                     "anthropic-version": "2023-06-01",
+                     "anthropic-beta": "message-batches-2024-09-24"},
+            timeout=self.args.timeout or 60, **kwargs)
+        if not response.ok:
+            print(
+                f"Error: the batches API returned {response.status_code}: {response.text}", file=sys.stderr)
+            sys.exit(1)
+        return response
+
+    def batch_submit(self, path):
+        """Submit every input of a JSONL file as one Message Batch with the current pattern, and print the batch ID.
+
+        Each line is either a JSON string or an object with an "input" and an optional "custom_id".
+        """
+        if not self.claude:
+            print(
+                f"Error: batches are sent to Anthropic, but {self.model} is not a Claude model. Choose one with --model.", file=sys.stderr)
+            sys.exit(1)
+        system = ""
+        if self.pattern:
+            try:
+                with open(self.pattern_file(), "r") as f:
+                    system = f.read()
+            except FileNotFoundError:
+                print("pattern not found")
+                self.failed = True
+                return
+        batch = []
+        with open(path, "r") as f:
+            for number, line in enumerate(f, 1):
+                if not line.strip():
+                    continue
+                try:
+                    entry = json.loads(line)
+                    if isinstance(entry, str):
+                        entry = {"input": entry}
+                    user = entry["input"]
+                except (ValueError, KeyError, TypeError):
+                    print(
+                        f"Error: line {number} of {path} is neither a JSON string nor an object with an \"input\"", file=sys.stderr)
+                    sys.exit(1)
+                options = self.claude_options(
+                    self.render_pattern(system, user))
+                options.pop("extra_headers", None)
+                options.update(options.pop("extra_body", {}))
+                if not system:
+                    del options["system"]
+                batch.append({
+                    "custom_id": str(entry.get("custom_id") or f"line-{number}"),
+                    "params": {"model": self.model, "max_tokens": 4096,
+                               "messages": [{"role": "user", "content": self.apply_user_template(user)}],
+                               "temperature": self.args.temp, "top_p": self.args.top_p, **options},
+                })
+        if not batch:
+            print(f"Error: {path} has no inputs", file=sys.stderr)
+            sys.exit(1)
+        response = self.anthropic_batches("POST", json={"requests": batch})
+        print(response.json()["id"])
+
+    def batch_status(self, batch_id):
+        """Print the processing status of a Message Batch and how many of its requests are in each state."""
+        batch = self.anthropic_batches(
+            "GET", f"https://api.anthropic.com/v1/messages/batches/{batch_id}").json()
+        counts = ", ".join(f"{count} {state}" for state,
+                           count in batch["request_counts"].items())
+        print(f"{batch['processing_status']}: {counts}")
+
+    def batch_results(self, batch_id):
+        """Print the results of an ended Message Batch as JSONL, one object with the custom_id and the output or error per request."""
+        batch = self.anthropic_batches(
+            "GET", f"https://api.anthropic.com/v1/messages/batches/{batch_id}").json()
+        if batch["processing_status"] != "ended":
+            print(
+                f"Error: batch {batch_id} is still {batch['processing_status']}. Check on it with --batch-status.", file=sys.stderr)
+            sys.exit(1)
+        for line in self.anthropic_batches("GET", batch["results_url"]).text.splitlines():
+            if not line.strip():
+                continue
+            entry = json.loads(line)
+            result = entry["result"]
+            if result["type"] == "succeeded":
+                output = {"output": "".join(block.get("text", "")
+                                            for block in result["message"]["content"])}
+            else:
+                output = {"error": result.get("error", {}).get(
+                    "error", {}).get("message") or result["type"]}
+            print(json.dumps({"custom_id": entry["custom_id"], **output}))
+
+    def send_structured(self, messages):
+        """Ask for a reply that is valid against the --json-schema and print only the JSON.
+
+        A reply that isn't valid JSON or doesn't match the schema is sent back with the
+        reason, up to two more times, before giving up with exit status 1.
+        """
+        try:
+            import jsonschema
+            with open(self.args.json_schema, "r") as f:
+                schema = json.load(f)
+            jsonschema.validators.validator_for(schema).check_schema(schema)
+        except ImportError:
+            print("Error: --json-schema needs the jsonschema package", file=sys.stderr)
+            sys.exit(1)
+        except (OSError, ValueError, jsonschema.SchemaError) as e:
+            print(
+                f"Error: {self.args.json_schema} is not a usable JSON schema: {e}", file=sys.stderr)
+            sys.exit(1)
+        system = "\n".join(message["content"]
+                           for message in messages if message["role"] == "system")
+        instructions = "Reply with a single JSON value and nothing else. It must be valid against this JSON schema:\n" + \
+            json.dumps(schema, indent=2)
+        user = messages[-1]["content"]
+        request = user
+        for attempt in range(1, 4):
+            try:
+                reply = self.structured_reply(
+                    (system + "\n\n" if system else "") + instructions, request, schema).strip()
+            except Exception as e:
+                print(f"Error: {e}")
+                return
+            if reply.startswith("```"):
+                # Some models wrap the JSON in a Markdown code block anyway
+                reply = reply.split("\n", 1)[-1].rsplit("```", 1)[0]
+            try:
+                result = json.loads(reply)
+                jsonschema.validate(result, schema)
+                break
+            except json.JSONDecodeError as e:
+                error = f"it is not valid JSON ({e})"
+            except jsonschema.ValidationError as e:
+                error = f"it does not match the schema ({e.message})"
+            print(f"Reply {attempt} was rejected: {error}", file=sys.stderr)
+            request = f"{user}\n\nYour last reply was rejected because {error}. Reply again with corrected JSON only."
+        else:
+            print(
+                f"Error: {self.model} did not return valid JSON in 3 attempts", file=sys.stderr)
+            sys.exit(1)
+        output = json.dumps(result, indent=2, ensure_ascii=False)
+        print(output)
+        if self.args.copy:
+            pyperclip.copy(output)
+        if self.args.output:
+            with open(self.args.output, "w") as f:
+                f.write(output)
+        if self.args.session:
+            from .helper import Session
+            Session().save_to_session(system, user, output, self.args.session)
+        self.remember_request(messages)
+
+    def structured_reply(self, system, user, schema):
+        """Send one non-streaming request for JSON and return the reply text, turning on the backend's JSON mode where it has one."""
+        backend = self.backend()
+        messages = [{"role": "system", "content": system},
+                    {"role": "user", "content": user}]
+        if backend == "ollama":
+            import ollama
+            client = ollama.Client(host=getattr(self.args, 'remoteOllamaServer', None),
+                                   timeout=self.args.timeout, headers=provider_extras("ollama")[0])
+            response = client.chat(model=self.model, messages=self.with_attachments(messages),
+                                   options=self.ollama_options(), format="json")
+            return response['message']['content']
+        if backend == "claude":
+            from anthropic import Anthropic
+            client = Anthropic(api_key=os.environ["CLAUDE_API_KEY"],
+                               **client_options(self.args))
+            message = client.messages.create(
+                max_tokens=4096, **self.claude_options(system),
+                messages=self.with_attachments([messages[-1]]), model=self.model,
+                temperature=self.args.temp, top_p=self.args.top_p)
+            return message.content[0].text
+        if backend == "google":
+            import google.generativeai as genai
+            genai.configure(api_key=os.environ["GOOGLE_API_KEY"])
+            model = genai.GenerativeModel(
+                model_name=self.model, system_instruction=system)
+            response = model.generate_content(
+                self.google_content(user),
+                generation_config={**self.google_generation_config(),
+                                   "response_mime_type": "application/json"},
+                request_options=self.google_request_options())
+            return response.text
+        options = self.openai_options()
+        if backend == "openai":
+            options["response_format"] = {"type": "json_schema",
+                                          "json_schema": {"name": "response", "schema": schema}}
+        response = self.chat_client().chat.completions.create(
+            model=self.model,
+            messages=self.with_attachments(messages),
+            temperature=self.args.temp,
+            top_p=self.args.top_p,
+            frequency_penalty=self.args.frequency_penalty,
+            presence_penalty=self.args.presence_penalty,
+            **options,
+        )
+        return response.choices[0].message.content
+
+    def print_token_count(self, messages):
+        """Print how many input tokens the assembled messages take for the selected model, without sending them."""
+        try:
+            count, exact = self.count_tokens(messages)
+        except Exception as e:
+            print(f"Error: couldn't count the tokens: {e}", file=sys.stderr)
+            sys.exit(1)
+        if exact:
+            print(f"{count} input tokens for {self.model}")
+        else:
+            print(
+                f"About {count} input tokens for {self.model}, estimated with an OpenAI tokenizer")
+
+    def count_tokens(self, messages):
+        """Count the input tokens of the messages, and tell whether the count is exact for the selected model.
+
+        Claude and Gemini models are counted by their API. Everything else is counted locally
+        with tiktoken, which is exact only for OpenAI's own models and leaves out attachments.
+        """
+        system = "\n".join(message["content"]
+                           for message in messages if message["role"] == "system")
+        user = messages[-1]
+        backend = self.backend()
+        if backend == "claude":
+            response = requests.post(
+                "https://api.anthropic.com/v1/messages/count_tokens",
+                headers={"x-api-key": os.environ["CLAUDE_API_KEY"],
+                         "anthropic-version": "2023-06-01",
+                         "anthropic-beta": "token-counting-2024-11-01"},
+                json={"model": self.model, "system": system,
+                      "messages": self.with_attachments([user])},
+                timeout=self.args.timeout or 30)
+            response.raise_for_status()
+            return response.json()["input_tokens"], True
+        if backend == "google":
+            import google.generativeai as genai
+            genai.configure(api_key=os.environ["GOOGLE_API_KEY"])
+            model = genai.GenerativeModel(
+                model_name=self.model, system_instruction=system or None)
+            return model.count_tokens(self.google_content(user["content"])).total_tokens, True
+        import tiktoken
+        try:
+            encoding = tiktoken.encoding_for_model(self.model)
+            exact = backend == "openai" and not self.attachments
+        except KeyError:
+            encoding = tiktoken.get_encoding("cl100k_base")
+            exact = False
+        # Each message takes a few tokens of framing besides its content
+        return sum(len(encoding.encode(message["content"])) + 3 for message in messages) + 3, exact
+
+    def with_attachments(self, messages):
+        """Return the messages with the --attach files added to the last (user) message, in the selected backend's format."""
+        if not self.attachments:
+            return messages
+        user = dict(messages[-1])
+        text = user["content"]
+        backend = self.backend()
+        if backend != "claude" and any(mime == "application/pdf" for mime, _, _ in self.attachments):
+            print(
+                "Error: PDF attachments are only supported for Claude and Gemini models", file=sys.stderr)
+            sys.exit(1)
+        if backend == "ollama":
+            user["images"] = [data for _, data, _ in self.attachments]
+        elif backend == "claude":
+            user["content"] = [{"type": "document" if mime == "application/pdf" else "image",
+                                "source": {"type": "base64", "media_type": mime, "data": data}}
+                               for mime, data, _ in self.attachments] + [{"type": "text", "text": text}]
+        else:
+            user["content"] = [{"type": "text", "text": text}] + [
+                {"type": "image_url", "image_url": {"url": f"data:{mime};base64,{data}"}} for mime, data, _ in self.attachments]
+        return messages[:-1] + [user]
+
+    def google_content(self, user):
+        """Return the content of a Gemini request: the user text followed by any --attach files."""
+        if not self.attachments:
+            return user
+        return [user] + [{"mime_type": mime, "data": base64.b64decode(data)} for mime, data, _ in self.attachments]
+
+    def read_session_history(self):
+        """Read the session's prior conversation, unless --ignore-history suppresses it for this request."""
+        if getattr(self.args, 'ignore_history', False):
+            print(
+                f"Ignoring the history of session {self.args.session} for this request", file=sys.stderr)
+            return ""
+        from .helper import Session
+        session = Session()
+        return session.read_from_session(self.args.session)
+
+    def render_pattern(self, text, input_data):
+        """Turn the text of a system.md into the system prompt: drop its frontmatter and fill in its variables.
+
+        Exits with an error when a variable the frontmatter requires wasn't given with -v.
+        The --strategy prompt, if any, is appended.
+        """
+        metadata, text = split_frontmatter(text)
+        given = dict(getattr(self.args, 'variable', None) or [])
+        missing = [name for name in metadata.get("variables") or []
+                   if name not in given and name not in ["input", "date"]]
+        if missing:
+            print(
+                f"Error: pattern {self.pattern} needs " + ", ".join(f"-v {name}=..." for name in missing), file=sys.stderr)
+            sys.exit(1)
+        text = self.apply_template_vars(text, input_data)
+        if self.strategy:
+            text += "\n\n" + self.strategy
+        return text
+
+    def apply_user_template(self, input_data):
+        """Prepend the pattern's user.md, with its variables filled in, to the input.
+
+        A user.md that uses {{input}} replaces the input instead. Without a non-empty user.md the input is unchanged.
+        """
+        if not self.pattern:
+            return input_data
+        path = pattern_user_path(self.pattern)
+        if not path or not os.path.exists(path):
+            return input_data
+        with open(path, "r") as f:
+            template = f.read().rstrip("\n")
+        if not template.strip():
+            return input_data
+        if re.search(r"\{\{\s*input\s*\}\}", template):
+            return self.apply_template_vars(template, input_data)
+        return self.apply_template_vars(template, input_data) + "\n" + input_data
+
+    def apply_template_vars(self, text, input_data):
+        """Fill in the {{input}}, {{date}} and -v name=value variables of a pattern. Unknown {{names}} are left as they are."""
+        variables = {"date": date.today().isoformat(),
+                     **dict(getattr(self.args, 'variable', None) or []),
+                     "input": input_data}
+        return re.sub(r"\{\{\s*(\w+)\s*\}\}", lambda match: variables.get(match.group(1), match.group(0)), text)
+
+    def warn_if_empty_system(self, pattern_system):
+        """Warn on stderr when a pattern's system.md is empty, which is usually a broken pattern."""
+        if pattern_system.strip() or getattr(self.args, 'allow_empty_system', False):
+            return
+        print(
+            f"Warning: the system.md of pattern {self.pattern} is empty. Pass --allow-empty-system if this is intended.", file=sys.stderr)
+
+    def claude_options(self, system):
+        """Return the system prompt of a Claude request, marked as cacheable under --cache-prompt, with the optional sampling parameters and the beta headers it needs."""
+        options = {"system": system}
+        if getattr(self.args, 'top_k', None) is not None:
+            options["top_k"] = self.args.top_k
+        if getattr(self.args, 'stop', None):
+            options["stop_sequences"] = self.args.stop
+        betas = []
+        if getattr(self.args, 'cache_prompt', False) and system.strip():
+            options["system"] = [
+                {"type": "text", "text": system, "cache_control": {"type": "ephemeral"}}]
+            betas.append("prompt-caching-2024-07-31")
+        if any(mime == "application/pdf" for mime, _, _ in self.attachments):
+            betas.append("pdfs-2024-09-25")
+        headers, body = provider_extras("claude")
+        if betas:
+            betas = [headers["anthropic-beta"]] + betas if headers.get("anthropic-beta") else betas
+            headers = {**headers, "anthropic-beta": ",".join(betas)}
+        if headers:
+            options["extra_headers"] = headers
+        if body:
+            options["extra_body"] = body
+        return options
+
+    def report_cache_usage(self, usage):
+        """Print to stderr how much of the prompt was read from or written to Claude's cache."""
+        if not getattr(self.args, 'cache_prompt', False):
+            return
+        read = getattr(usage, 'cache_read_input_tokens', None) or 0
+        written = getattr(usage, 'cache_creation_input_tokens', None) or 0
+        print(
+            f"Prompt cache: {read} tokens read, {written} tokens written", file=sys.stderr)
+
+    def report_stats(self, input_tokens, output_tokens):
+        """Print the token usage, estimated cost and latency of the request to stderr under --stats."""
+        if not getattr(self.args, 'stats', False):
+            return
+        latency = time.monotonic() - self.request_started
+        if self.local:
+            cost = "no cost (local model)"
+        elif input_tokens is None or output_tokens is None or model_price(self.model) is None:
+            cost = "cost unknown"
+        else:
+            input_price, output_price = model_price(self.model)
+            cost = f"about ${(input_tokens * input_price + output_tokens * output_price) / 1_000_000:.4f}"
+        print(
+            f"Stats: {input_tokens if input_tokens is not None else '?'} input tokens, "
+            f"{output_tokens if output_tokens is not None else '?'} output tokens, {cost}, {latency:.1f}s",
+            file=sys.stderr)
+
+    def report_google_stats(self, response):
+        """Report the usage of a Gemini response under --stats."""
+        usage = getattr(response, 'usage_metadata', None)
+        self.report_stats(getattr(usage, 'prompt_token_count', None),
+                          getattr(usage, 'candidates_token_count', None))
+
+    def check_refusal(self, stop_reason):
+        """Tell a refusal apart from a normal answer: warn on stderr, and exit with status 3 under --strict."""
+        if stop_reason != "refusal":
+            return
+        print(
+            f"Warning: {self.model} refused to answer this request.", file=sys.stderr)
+        if getattr(self.args, 'strict', False):
+            sys.exit(3)
+
+    @contextmanager
+    def partial_output(self):
+        """Yield a function that saves streamed chunks to the session's .partial file.
+
+        The file survives a crash, so the next run can offer to recover the response.
+        Without a session the function does nothing.
+        """
+        if not self.args.session:
+            yield lambda text: None
+            return
+        from .helper import Session
+        with Session().open_partial(self.args.session) as partial:
+            def write_partial(text):
+                partial.write(text)
+                partial.flush()
+            yield write_partial
 
     async def localChat(self, messages, host=''):
         from ollama import AsyncClient
         response = None
         if host:
-            response = await AsyncClient(host=host).chat(model=self.model, messages=messages)
+            response = await AsyncClient(host=host, timeout=self.args.timeout, headers=provider_extras("ollama")[0]).chat(model=self.model, messages=self.with_attachments(messages), options=self.ollama_options())
         else:
-            response = await AsyncClient().chat(model=self.model, messages=messages)
+            response = await AsyncClient(timeout=self.args.timeout, headers=provider_extras("ollama")[0]).chat(model=self.model, messages=self.with_attachments(messages), options=self.ollama_options())
         print(response['message']['content'])
+        self.report_stats(response.get('prompt_eval_count'),
+                          response.get('eval_count'))
         copy = self.args.copy
         if copy:
             pyperclip.copy(response['message']['content'])
@@ -80,14 +676,20 @@ class Standalone:
     async def localStream(self, messages, host=''):
         from ollama import AsyncClient
         buffer = ""
-        if host:
-            async for part in await AsyncClient(host=host).chat(model=self.model, messages=messages, stream=True):
-                buffer += part['message']['content']
-                print(part['message']['content'], end='', flush=True)
-        else:
-            async for part in await AsyncClient().chat(model=self.model, messages=messages, stream=True):
-                buffer += part['message']['content']
-                print(part['message']['content'], end='', flush=True)
+        # The last part carries the token counts of the whole response
+        part = {}
+        with self.partial_output() as write_partial:
+            if host:
+                async for part in await AsyncClient(host=host, timeout=self.args.timeout, headers=provider_extras("ollama")[0]).chat(model=self.model, messages=self.with_attachments(messages), options=self.ollama_options(), stream=True):
+                    buffer += part['message']['content']
+                    write_partial(part['message']['content'])
+                    print(part['message']['content'], end='', flush=True)
+            else:
+                async for part in await AsyncClient(timeout=self.args.timeout, headers=provider_extras("ollama")[0]).chat(model=self.model, messages=self.with_attachments(messages), options=self.ollama_options(), stream=True):
+                    buffer += part['message']['content']
+                    write_partial(part['message']['content'])
+                    print(part['message']['content'], end='', flush=True)
+        self.report_stats(part.get('prompt_eval_count'), part.get('eval_count'))
         if self.args.output:
             with open(self.args.output, "w") as f:
                 f.write(buffer)
@@ -97,18 +699,22 @@ class Standalone:
     async def claudeStream(self, system, user):
         from anthropic import AsyncAnthropic
         self.claudeApiKey = os.environ["CLAUDE_API_KEY"]
-        Streamingclient = AsyncAnthropic(api_key=self.claudeApiKey)
+        Streamingclient = AsyncAnthropic(
+            api_key=self.claudeApiKey, **client_options(self.args))
         buffer = ""
         async with Streamingclient.messages.stream(
             max_tokens=4096,
-            system=system,
-            messages=[user],
+            **self.claude_options(system),
+            messages=self.with_attachments([user]),
             model=self.model, temperature=self.args.temp, top_p=self.args.top_p
         ) as stream:
-            async for text in stream.text_stream:
-                buffer += text
-                print(text, end="", flush=True)
+            with self.partial_output() as write_partial:
+                async for text in stream.text_stream:
+                    buffer += text
+                    write_partial(text)
+                    print(text, end="", flush=True)
             print()
+            message = await stream.get_final_message()
         if self.args.copy:
             pyperclip.copy(buffer)
         if self.args.output:
@@ -117,19 +723,24 @@ class Standalone:
         if self.args.session:
             from .helper import Session
             session = Session()
+            session.clear_partial(self.args.session)
             session.save_to_session(
                 system, user, buffer, self.args.session)
-        message = await stream.get_final_message()
+        self.report_cache_usage(message.usage)
+        self.report_stats(message.usage.input_tokens,
+                          message.usage.output_tokens)
+        self.check_refusal(message.stop_reason)
 
     async def claudeChat(self, system, user, copy=False):
         from anthropic import Anthropic
         self.claudeApiKey = os.environ["CLAUDE_API_KEY"]
-        client = Anthropic(api_key=self.claudeApiKey)
+        client = Anthropic(api_key=self.claudeApiKey,
+                           **client_options(self.args))
         message = None
         message = client.messages.create(
             max_tokens=4096,
-            system=system,
-            messages=[user],
+            **self.claude_options(system),
+            messages=self.with_attachments([user]),
             model=self.model,
             temperature=self.args.temp, top_p=self.args.top_p
         )
@@ -145,6 +756,10 @@ class Standalone:
             session = Session()
             session.save_to_session(
                 system, user, message.content[0].text, self.args.session)
+        self.report_cache_usage(message.usage)
+        self.report_stats(message.usage.input_tokens,
+                          message.usage.output_tokens)
+        self.check_refusal(message.stop_reason)
 
     async def googleChat(self, system, user, copy=False):
         import google.generativeai as genai
@@ -152,8 +767,11 @@ class Standalone:
         genai.configure(api_key=self.googleApiKey)
         model = genai.GenerativeModel(
             model_name=self.model, system_instruction=system)
-        response = model.generate_content(user)
+        response = model.generate_content(
+            self.google_content(user), generation_config=self.google_generation_config(),
+            request_options=self.google_request_options())
         print(response.text)
+        self.report_google_stats(response)
         if copy:
             pyperclip.copy(response.text)
         if self.args.output:
@@ -172,10 +790,15 @@ class Standalone:
         genai.configure(api_key=self.googleApiKey)
         model = genai.GenerativeModel(
             model_name=self.model, system_instruction=system)
-        response = model.generate_content(user, stream=True)
-        for chunk in response:
-            buffer += chunk.text
-            print(chunk.text)
+        response = model.generate_content(
+            self.google_content(user), stream=True, generation_config=self.google_generation_config(),
+            request_options=self.google_request_options())
+        with self.partial_output() as write_partial:
+            for chunk in response:
+                buffer += chunk.text
+                write_partial(chunk.text)
+                print(chunk.text)
+        self.report_google_stats(response)
         if copy:
             pyperclip.copy(buffer)
         if self.args.output:
@@ -184,6 +807,7 @@ class Standalone:
         if self.args.session:
             from .helper import Session
             session = Session()
+            session.clear_partial(self.args.session)
             session.save_to_session(
                 system, user, buffer, self.args.session)
 
@@ -200,39 +824,40 @@ class Standalone:
             FileNotFoundError: If the pattern file is not found.
         """
 
-        wisdomFilePath = os.path.join(
-            config_directory, f"patterns/{self.pattern}/system.md"
-        )
+        wisdomFilePath = self.pattern_file()
         session_message = ""
         user = ""
         if self.args.session:
-            from .helper import Session
-            session = Session()
-            session_message = session.read_from_session(
-                self.args.session)
+            session_message = self.read_session_history()
         if session_message:
             user = session_message + '\n' + input_data
         else:
             user = input_data
-        user_message = {"role": "user", "content": f"{input_data}"}
+        user_message = {"role": "user",
+                        "content": self.apply_user_template(input_data)}
         wisdom_File = wisdomFilePath
         buffer = ""
         system = ""
+        completed = False
         if self.pattern:
             try:
                 with open(wisdom_File, "r") as f:
+                    pattern_system = self.render_pattern(
+                        f.read(), input_data)
                     if context:
-                        system = context + '\n\n' + f.read()
+                        system = context + '\n\n' + pattern_system
                         if session_message:
                             system = session_message + '\n' + system
                     else:
-                        system = f.read()
+                        system = pattern_system
                         if session_message:
                             system = session_message + '\n' + system
                     system_message = {"role": "system", "content": system}
+                self.warn_if_empty_system(pattern_system)
                 messages = [system_message, user_message]
             except FileNotFoundError:
                 print("pattern not found")
+                self.failed = True
                 return
         else:
             if session_message:
@@ -243,6 +868,20 @@ class Standalone:
                     {"role": "system", "content": context}, user_message]
             else:
                 messages = [user_message]
+        if getattr(self.args, 'prompt_only', False):
+            self.print_prompt(messages)
+            return
+        self.ensure_model_allowed()
+        if getattr(self.args, 'counttokens', False):
+            self.print_token_count(messages)
+            return
+        if self.unchanged_since_last_run(messages):
+            print("no change, skipping", file=sys.stderr)
+            return
+        if getattr(self.args, 'json_schema', None):
+            self.send_structured(messages)
+            return
+        self.request_started = time.monotonic()
         try:
             if self.local:
                 if host:
@@ -257,33 +896,61 @@ class Standalone:
                     system = " "
                 asyncio.run(self.googleStream(system, user_message['content']))
             else:
-                stream = self.client.chat.completions.create(
+                stream = self.chat_client().chat.completions.create(
                     model=self.model,
-                    messages=messages,
+                    messages=self.with_attachments(messages),
                     temperature=self.args.temp,
                     top_p=self.args.top_p,
                     frequency_penalty=self.args.frequency_penalty,
                     presence_penalty=self.args.presence_penalty,
+                    **self.openai_options(stream=True),
                     stream=True,
                 )
-                for chunk in stream:
-                    if chunk.choices[0].delta.content is not None:
-                        char = chunk.choices[0].delta.content
-                        buffer += char
-                        if char not in ["\n", " "]:
-                            print(char, end="")
-                        elif char == " ":
-                            print(" ", end="")  # Explicitly handle spaces
-                        elif char == "\n":
-                            print()  # Handle newlines
-                    sys.stdout.flush()
+                citations = None
+                usage = None
+                with self.partial_output() as write_partial:
+                    for chunk in stream:
+                        # With usage requested, the last chunk has the usage and no choices
+                        usage = getattr(chunk, 'usage', None) or usage
+                        if not chunk.choices:
+                            continue
+                        # Perplexity sends the sources with every chunk; the last one is complete
+                        citations = getattr(chunk, 'citations', None) or citations
+                        # deepseek-reasoner streams its reasoning before the answer; keep it off stdout
+                        reasoning = getattr(
+                            chunk.choices[0].delta, 'reasoning_content', None)
+                        if reasoning:
+                            print(reasoning, end="", file=sys.stderr, flush=True)
+                        if chunk.choices[0].delta.content is not None:
+                            char = chunk.choices[0].delta.content
+                            buffer += char
+                            write_partial(char)
+                            if char not in ["\n", " "]:
+                                print(char, end="")
+                            elif char == " ":
+                                print(" ", end="")  # Explicitly handle spaces
+                            elif char == "\n":
+                                print()  # Handle newlines
+                        sys.stdout.flush()
+                if citations and getattr(self.args, 'citations', False):
+                    footnotes = self.format_citations(citations)
+                    buffer += footnotes
+                    print(footnotes, end="")
+                self.report_stats(getattr(usage, 'prompt_tokens', None),
+                                  getattr(usage, 'completion_tokens', None))
+            completed = True
+            self.remember_request(messages)
         except Exception as e:
+            self.failed = True
             if "All connection attempts failed" in str(e):
                 print(
                     "Error: cannot connect to llama2. If you have not already, please visit https://ollama.com for installation instructions")
             if "CLAUDE_API_KEY" in str(e):
                 print(
                     "Error: CLAUDE_API_KEY not found in environment variables. Please run --setup and add the key")
+            if "timed out" in str(e).lower():
+                print(
+                    "Error: the request timed out. Pass a larger --timeout if the model needs longer.")
             if "overloaded_error" in str(e):
                 print(
                     "Error: Fabric is working fine, but claude is overloaded. Please try again later.")
@@ -298,6 +965,10 @@ class Standalone:
         if self.args.session:
             from .helper import Session
             session = Session()
+            # After an error the chunks are only kept in the .partial file, unless they
+            # were streamed here and are about to be saved anyway.
+            if completed or buffer:
+                session.clear_partial(self.args.session)
             session.save_to_session(
                 system, user, buffer, self.args.session)
 
@@ -314,36 +985,36 @@ class Standalone:
             FileNotFoundError: If the specified pattern file is not found.
         """
 
-        wisdomFilePath = os.path.join(
-            config_directory, f"patterns/{self.pattern}/system.md"
-        )
+        wisdomFilePath = self.pattern_file()
         user = input_data
-        user_message = {"role": "user", "content": f"{input_data}"}
+        user_message = {"role": "user",
+                        "content": self.apply_user_template(input_data)}
         wisdom_File = os.path.join(current_directory, wisdomFilePath)
         system = ""
         session_message = ""
         if self.args.session:
-            from .helper import Session
-            session = Session()
-            session_message = session.read_from_session(
-                self.args.session)
+            session_message = self.read_session_history()
         if self.pattern:
             try:
                 with open(wisdom_File, "r") as f:
+                    pattern_system = self.render_pattern(
+                        f.read(), input_data)
                     if context:
                         if session_message:
-                            system = session_message + '\n' + context + '\n\n' + f.read()
+                            system = session_message + '\n' + context + '\n\n' + pattern_system
                         else:
-                            system = context + '\n\n' + f.read()
+                            system = context + '\n\n' + pattern_system
                     else:
                         if session_message:
-                            system = session_message + '\n' + f.read()
+                            system = session_message + '\n' + pattern_system
                         else:
-                            system = f.read()
+                            system = pattern_system
                     system_message = {"role": "system", "content": system}
+                self.warn_if_empty_system(pattern_system)
                 messages = [system_message, user_message]
             except FileNotFoundError:
                 print("pattern not found")
+                self.failed = True
                 return
         else:
             if session_message:
@@ -354,6 +1025,20 @@ class Standalone:
                     {'role': 'system', 'content': context}, user_message]
             else:
                 messages = [user_message]
+        if getattr(self.args, 'prompt_only', False):
+            self.print_prompt(messages)
+            return
+        self.ensure_model_allowed()
+        if getattr(self.args, 'counttokens', False):
+            self.print_token_count(messages)
+            return
+        if self.unchanged_since_last_run(messages):
+            print("no change, skipping", file=sys.stderr)
+            return
+        if getattr(self.args, 'json_schema', None):
+            self.send_structured(messages)
+            return
+        self.request_started = time.monotonic()
         try:
             if self.local:
                 if host:
@@ -367,26 +1052,39 @@ class Standalone:
                     system = " "
                 asyncio.run(self.googleChat(system, user_message['content']))
             else:
-                response = self.client.chat.completions.create(
+                response = self.chat_client().chat.completions.create(
                     model=self.model,
-                    messages=messages,
+                    messages=self.with_attachments(messages),
                     temperature=self.args.temp,
                     top_p=self.args.top_p,
                     frequency_penalty=self.args.frequency_penalty,
                     presence_penalty=self.args.presence_penalty,
+                    **self.openai_options(),
                 )
-                print(response.choices[0].message.content)
+                content = response.choices[0].message.content
+                reasoning = getattr(
+                    response.choices[0].message, 'reasoning_content', None)
+                if reasoning:
+                    print(reasoning, file=sys.stderr)
+                citations = getattr(response, 'citations', None)
+                if citations and getattr(self.args, 'citations', False):
+                    content += self.format_citations(citations)
+                print(content)
+                self.report_stats(getattr(response.usage, 'prompt_tokens', None),
+                                  getattr(response.usage, 'completion_tokens', None))
                 if self.args.copy:
-                    pyperclip.copy(response.choices[0].message.content)
+                    pyperclip.copy(content)
                 if self.args.output:
                     with open(self.args.output, "w") as f:
-                        f.write(response.choices[0].message.content)
+                        f.write(content)
                 if self.args.session:
                     from .helper import Session
                     session = Session()
                     session.save_to_session(
                         system, user, response.choices[0], self.args.session)
+            self.remember_request(messages)
         except Exception as e:
+            self.failed = True
             if "All connection attempts failed" in str(e):
                 print(
                     "Error: cannot connect to llama2. If you have not already, please visit https://ollama.com for installation instructions")
@@ -396,6 +1094,9 @@ class Standalone:
             if "overloaded_error" in str(e):
                 print(
                     "Error: Fabric is working fine, but claude is overloaded. Please try again later.")
+            if "timed out" in str(e).lower():
+                print(
+                    "Error: the request timed out. Pass a larger --timeout if the model needs longer.")
             if "Attempted to call a sync iterator on an async stream" in str(e):
                 print("Error: There is a problem connecting fabric with your local ollama installation. Please visit https://ollama.com for installation instructions. It is possible that you have chosen the wrong model. Please run fabric --listmodels to see the available models and choose the right one with fabric --model <model> or fabric --changeDefaultModel. If this does not work. Restart your computer (always a good idea) and try again. If you are still having problems, please visit https://ollama.com for installation instructions.")
             else:
@@ -406,9 +1107,20 @@ class Standalone:
         gptlist = []
         fullOllamaList = []
         googleList = []
+        compatibleModels = {}
+        for provider in compatible_providers():
+            client = self.compatible_clients.get(provider["name"])
+            if client is None:
+                continue
+            models = provider["models"]
+            if models is None:
+                try:
+                    models = sorted(model.id for model in client.models.list().data)
+                except Exception:
+                    models = []
+            compatibleModels[provider["name"]] = models
         if "CLAUDE_API_KEY" in os.environ:
-            claudeList = ['claude-3-5-sonnet-20240620','claude-3-opus-20240229', 'claude-3-sonnet-20240229',
-                          'claude-3-haiku-20240307', 'claude-2.1']
+            claudeList = self.fetch_claude_models()
         else:
             claudeList = []
 
@@ -450,7 +1162,82 @@ class Standalone:
         except:
             googleList = []
 
-        return gptlist, fullOllamaList, claudeList, googleList
+        return (usable_models("openai", gptlist, self.args),
+                usable_models("ollama", fullOllamaList, self.args),
+                usable_models("claude", claudeList, self.args),
+                usable_models("google", googleList, self.args),
+                {name: usable_models(name, models, self.args) for name, models in compatibleModels.items()})
+
+    def openai_options(self, stream=False):
+        """Return the optional parameters of an OpenAI-style request, with the provider's extra headers and body fields. OpenAI has no top_k."""
+        options = {}
+        if getattr(self.args, 'stop', None):
+            options["stop"] = self.args.stop
+        if getattr(self.args, 'seed', None) is not None:
+            options["seed"] = self.args.seed
+        headers, body = provider_extras(self.backend())
+        if stream and getattr(self.args, 'stats', False):
+            # A stream only ends with a usage chunk when asked to
+            body = {**body, "stream_options": {"include_usage": True}}
+        if headers:
+            options["extra_headers"] = headers
+        if body:
+            options["extra_body"] = body
+        return options
+
+    def ollama_options(self):
+        """Return the sampling options of an Ollama request."""
+        options = {"top_p": self.args.top_p}
+        if getattr(self.args, 'top_k', None) is not None:
+            options["top_k"] = self.args.top_k
+        if getattr(self.args, 'stop', None):
+            options["stop"] = self.args.stop
+        if getattr(self.args, 'seed', None) is not None:
+            options["seed"] = self.args.seed
+        return options
+
+    def google_generation_config(self):
+        """Return the sampling parameters of a Gemini request."""
+        config = {"top_p": self.args.top_p}
+        if getattr(self.args, 'top_k', None) is not None:
+            config["top_k"] = self.args.top_k
+        if getattr(self.args, 'stop', None):
+            config["stop_sequences"] = self.args.stop
+        return config
+
+    def google_request_options(self):
+        """Return the request options for Gemini, which takes its timeout per request."""
+        if self.args.timeout:
+            return {"timeout": self.args.timeout}
+        return None
+
+    def fetch_claude_models(self):
+        """List the Claude models from Anthropic's models API, or the known ones when it can't be reached."""
+        try:
+            response = requests.get(
+                "https://api.anthropic.com/v1/models",
+                headers={"x-api-key": os.environ["CLAUDE_API_KEY"],
+                         "anthropic-version": "2023-06-01"},
+                params={"limit": 1000}, timeout=10)
+            response.raise_for_status()
+            return [model["id"] for model in response.json()["data"]]
+        except Exception:
+            return ['claude-3-5-sonnet-20240620', 'claude-3-opus-20240229', 'claude-3-sonnet-20240229',
+                    'claude-3-haiku-20240307', 'claude-2.1']
+
+    def limit_input(self, text):
+        """Truncate input beyond --max-input-bytes, or exit with an error under --strict."""
+        max_bytes = getattr(self.args, 'max_input_bytes', None)
+        data = text.encode("utf-8")
+        if not max_bytes or len(data) <= max_bytes:
+            return text
+        if getattr(self.args, 'strict', False):
+            print(
+                f"Error: input is {len(data)} bytes, over the --max-input-bytes limit of {max_bytes}", file=sys.stderr)
+            sys.exit(1)
+        print(
+            f"Warning: input is {len(data)} bytes, truncating it to the --max-input-bytes limit of {max_bytes}", file=sys.stderr)
+        return data[:max_bytes].decode("utf-8", errors="ignore")
 
     def get_cli_input(self):
         """ aided by ChatGPT; uses platform library
@@ -485,6 +1272,13 @@ class Standalone:
         elif model in self.claudeList:
             print("Claude is not supported in this mode")
             sys.exit()
+        elif self.compatible == "azure":
+            print("Azure OpenAI is not supported in this mode")
+            sys.exit()
+        elif self.compatible:
+            provider = compatible_provider(self.compatible)
+            os.environ["OPENAI_API_BASE"] = provider["base_url"]
+            os.environ["OPENAI_API_KEY"] = os.environ[provider["key"]]
         print("Starting PraisonAI...")
         praison_ai = PraisonAI(auto=userInput, framework="autogen")
         praison_ai.main()
@@ -493,13 +1287,24 @@ class Standalone:
 class Update:
     def __init__(self):
         """Initialize the object with default values."""
-        self.repo_zip_url = "https://github.com/danielmiessler/fabric/archive/refs/heads/main.zip"
+        load_env_files()
+        repo_url = os.environ.get(
+            "PATTERNS_REPO_URL", "https://github.com/danielmiessler/fabric").rstrip("/")
+        branch = os.environ.get("PATTERNS_BRANCH", "main")
+        self.repo_zip_url = f"{repo_url}/archive/refs/heads/{branch}.zip"
         self.config_directory = os.path.expanduser("~/.config/fabric")
         self.pattern_directory = os.path.join(
             self.config_directory, "patterns")
+        # Hashes of the upstream files as last installed, used to spot local edits
+        self.manifest_file = os.path.join(
+            self.config_directory, "patterns_manifest.json")
         os.makedirs(self.pattern_directory, exist_ok=True)
         print("Updating patterns...")
         self.update_patterns()  # Start the update process immediately
+        for registry in pattern_registries():
+            if isinstance(registry, GitPatternRegistry):
+                print(f"Updating pattern registry {registry.url}...")
+                registry.sync()
 
     def update_patterns(self):
         """Update the patterns by downloading the zip from GitHub and extracting it."""
@@ -507,18 +1312,33 @@ class Update:
             zip_path = os.path.join(temp_dir, "repo.zip")
             self.download_zip(self.repo_zip_url, zip_path)
             extracted_folder_path = self.extract_zip(zip_path, temp_dir)
-            # The patterns folder will be inside "fabric-main" after extraction
+            # The patterns folder will be inside "<repo>-<branch>" after extraction
+            repo_folders = [folder for folder in os.listdir(extracted_folder_path) if os.path.isdir(
+                os.path.join(extracted_folder_path, folder))]
             patterns_source_path = os.path.join(
-                extracted_folder_path, "fabric-main", "patterns")
+                extracted_folder_path, repo_folders[0] if repo_folders else "", "patterns")
             if os.path.exists(patterns_source_path):
+                upstream_hashes = self.file_hashes(patterns_source_path)
                 # If the patterns directory already exists, remove it before copying over the new one
                 if os.path.exists(self.pattern_directory):
+                    manifest = self.load_manifest()
+                    installed_hashes = self.file_hashes(self.pattern_directory)
                     old_pattern_contents = os.listdir(self.pattern_directory)
                     new_pattern_contents = os.listdir(patterns_source_path)
                     custom_patterns = []
                     for pattern in old_pattern_contents:
                         if pattern not in new_pattern_contents:
                             custom_patterns.append(pattern)
+                        elif manifest is not None and self.is_locally_modified(pattern, installed_hashes, manifest):
+                            print(
+                                f"Keeping locally modified pattern: {pattern}")
+                            upstream_path = os.path.join(
+                                patterns_source_path, pattern)
+                            if os.path.isdir(upstream_path):
+                                shutil.rmtree(upstream_path)
+                            else:
+                                os.remove(upstream_path)
+                            custom_patterns.append(pattern)
                     if custom_patterns:
                         for pattern in custom_patterns:
                             custom_path = os.path.join(
@@ -526,10 +1346,54 @@ class Update:
                             shutil.move(custom_path, patterns_source_path)
                     shutil.rmtree(self.pattern_directory)
                 shutil.copytree(patterns_source_path, self.pattern_directory)
+                self.save_manifest(upstream_hashes)
                 print("Patterns updated successfully.")
             else:
                 print("Patterns folder not found in the downloaded zip.")
 
+    def file_hashes(self, directory):
+        """Return the sha256 of every file under directory, keyed by its path relative to directory.
+
+        Dotfiles and editor backups ending in ~ are skipped, so they don't count as local edits.
+        """
+        hashes = {}
+        for root, folders, files in os.walk(directory):
+            folders[:] = [name for name in folders if not name.startswith(".")]
+            for name in files:
+                if name.startswith(".") or name.endswith("~"):
+                    continue
+                path = os.path.join(root, name)
+                with open(path, "rb") as f:
+                    hashes[os.path.relpath(path, directory)] = hashlib.sha256(
+                        f.read()).hexdigest()
+        return hashes
+
+    def is_locally_modified(self, pattern, installed_hashes, manifest):
+        """Check whether a pattern's files differ from the upstream version last installed."""
+        def pattern_files(hashes):
+            return {path: digest for path, digest in hashes.items()
+                    if path == pattern or path.startswith(pattern + os.sep)}
+        return pattern_files(installed_hashes) != pattern_files(manifest)
+
+    def load_manifest(self):
+        """Load the upstream hashes recorded by the last update, or None if there are none or they can't be read."""
+        if not os.path.exists(self.manifest_file):
+            return None
+        try:
+            with open(self.manifest_file, "r") as f:
+                manifest = json.load(f)
+        except ValueError:
+            manifest = None
+        if not isinstance(manifest, dict):
+            print(
+                f"Warning: {self.manifest_file} is corrupt, so local edits to patterns can't be detected this time. It will be rewritten.", file=sys.stderr)
+            return None
+        return manifest
+
+    def save_manifest(self, hashes):
+        with open(self.manifest_file, "w") as f:
+            json.dump(hashes, f, indent=2)
+
     def download_zip(self, url, save_path):
         """Download the zip file from the specified URL."""
         response = requests.get(url)
@@ -731,10 +1595,7 @@ class Setup:
         """
         model = model.strip()
         env = os.path.expanduser("~/.config/fabric/.env")
-        standalone = Standalone(args=[], pattern="")
-        gpt, ollama, claude, google = standalone.fetch_available_models()
-        allmodels = gpt + ollama + claude + google
-        if model not in allmodels:
+        if model not in self.available_models():
             print(
                 f"Error: {model} is not a valid model. Please run fabric --listmodels to see the available models.")
             sys.exit()
@@ -768,6 +1629,30 @@ class Setup:
             else:
                 print("No shell configuration file found.")
 
+    def available_models(self):
+        """Return every model the configured providers offer."""
+        standalone = Standalone(args=[], pattern="")
+        gpt, ollama, claude, google, compatible = standalone.fetch_available_models()
+        return gpt + ollama + claude + google + \
+            [model for models in compatible.values() for model in models]
+
+    def choose_default_model(self):
+        """Ask for the default model until one the configured providers offer is given, or enter is pressed to skip."""
+        models = self.available_models()
+        if not models:
+            print("No models are available yet. Once they are, set the default with fabric --changeDefaultModel.")
+            return
+        while True:
+            model = input(
+                "Please enter the model to use by default, e.g. gpt-4-turbo-preview or claude-3-opus-20240229. Run fabric --listmodels later to see them all. To keep the current default, press enter.\n").strip()
+            if not model:
+                return
+            if model in models:
+                self.default_model(model)
+                return
+            print(
+                f"{model} is not offered by any of the providers you have set up.")
+
     def patterns(self):
         """        Method to update patterns and exit the system.
 
@@ -780,7 +1665,7 @@ class Setup:
     def run(self):
         """        Execute the Fabric program.
 
-        This method prompts the user for their OpenAI API key, sets the API key in the Fabric object, and then calls the patterns method.
+        This method prompts the user for their API keys and default model, writes them to the environment file, and then calls the patterns method.
 
         Returns:
             None
@@ -799,6 +1684,7 @@ class Setup:
         print("Please enter your YouTube API key. If you do not have one, or if you have already entered it, press enter.\n")
         youtubekey = input()
         self.youtube_key(youtubekey)
+        self.choose_default_model()
         self.patterns()
         self.update_shconfigs()
         self.__ensure_env_file_created()
@@ -863,6 +1749,895 @@ class AgentSetup:
                 f.write(f"\n{browserless_entry}\n{serper_entry}\n")
 
 
+def load_env_files(explicit_env_file=None, config_env_file=env_file):
+    """Load fabric's .env files without overriding variables that are already set.
+
+    Precedence, highest first: real environment variables, the --env-file given on the
+    command line, .fabric.env in the current directory, then ~/.config/fabric/.env.
+    """
+    if explicit_env_file and not os.path.exists(explicit_env_file):
+        print(
+            f"Warning: env file {explicit_env_file} does not exist", file=sys.stderr)
+    # load_dotenv never overrides a variable that is already set, so earlier files win
+    for file in env_files(explicit_env_file, config_env_file):
+        load_dotenv(file)
+
+
+def load_attachments(paths):
+    """Read the files passed with --attach, as (media type, base64 data, path) tuples.
+
+    Exits with an error for a file that is missing or isn't a supported image or PDF.
+    """
+    attachments = []
+    for path in paths or []:
+        mime, _ = mimetypes.guess_type(path)
+        if mime not in ["image/png", "image/jpeg", "image/gif", "image/webp", "application/pdf"]:
+            print(
+                f"Error: can't attach {path}: only PNG, JPEG, GIF and WebP images and PDFs are supported", file=sys.stderr)
+            sys.exit(1)
+        try:
+            with open(path, "rb") as f:
+                data = base64.b64encode(f.read()).decode("ascii")
+        except OSError as e:
+            print(f"Error: can't attach {path}: {e.strerror}", file=sys.stderr)
+            sys.exit(1)
+        attachments.append((mime, data, path))
+    return attachments
+
+
+def read_last_requests():
+    """Return the hash of the last request per session and pattern, as recorded by --only-on-change."""
+    try:
+        with open(last_requests_file, "r") as f:
+            return json.load(f)
+    except (FileNotFoundError, json.JSONDecodeError):
+        return {}
+
+
+def model_allowed(model):
+    """Check a model against ALLOWED_MODELS, a comma-separated list of glob patterns.
+
+    Every model is allowed when ALLOWED_MODELS is unset or empty.
+    """
+    patterns = [pattern.strip() for pattern in os.environ.get(
+        "ALLOWED_MODELS", "").split(",") if pattern.strip()]
+    if not patterns:
+        return True
+    return any(fnmatch.fnmatch(model, pattern) for pattern in patterns)
+
+
+def model_price(model):
+    """Return the USD price per million input and output tokens of a model, or None when it is not known.
+
+    Dated model names match the entry they start with, so gpt-4o-2024-08-06 is priced as gpt-4o.
+    """
+    prices = {
+        "gpt-4o": (2.50, 10.00),
+        "gpt-4o-mini": (0.15, 0.60),
+        "gpt-4-turbo": (10.00, 30.00),
+        "gpt-4": (30.00, 60.00),
+        "gpt-3.5-turbo": (0.50, 1.50),
+        "claude-3-5-sonnet": (3.00, 15.00),
+        "claude-3-5-haiku": (0.80, 4.00),
+        "claude-3-opus": (15.00, 75.00),
+        "claude-3-sonnet": (3.00, 15.00),
+        "claude-3-haiku": (0.25, 1.25),
+        "models/gemini-1.5-pro": (1.25, 5.00),
+        "models/gemini-1.5-flash": (0.075, 0.30),
+        "deepseek-chat": (0.27, 1.10),
+        "deepseek-reasoner": (0.55, 2.19),
+    }
+    matches = [name for name in prices if model.startswith(name)]
+    if not matches:
+        return None
+    return prices[max(matches, key=len)]
+
+
+def env_files(explicit_env_file=None, config_env_file=env_file):
+    """Return the existing .env files in order of precedence, highest first."""
+    candidates = [explicit_env_file, os.path.join(
+        os.getcwd(), ".fabric.env"), config_env_file]
+    return [file for file in candidates if file and os.path.exists(file)]
+
+
+def providers(ollama_host=None, args=None):
+    """Return the name, base URL and key status of every backend fabric can send to.
+
+    Keys are never returned, only whether one is configured.
+    """
+    load_env_files()
+    disabled = disabled_providers(args)
+    backends = [
+        {"name": "openai",
+         "base_url": os.environ.get("OPENAI_BASE_URL", "https://api.openai.com/v1"),
+         "needs_key": True,
+         "configured": "OPENAI_API_KEY" in os.environ},
+        {"name": "claude",
+         "base_url": "https://api.anthropic.com",
+         "needs_key": True,
+         "configured": "CLAUDE_API_KEY" in os.environ},
+        {"name": "google",
+         "base_url": "https://generativelanguage.googleapis.com",
+         "needs_key": True,
+         "configured": "GOOGLE_API_KEY" in os.environ},
+    ]
+    for provider in compatible_providers():
+        backends.append({"name": provider["name"],
+                         "base_url": provider["base_url"] or "(no endpoint set)",
+                         "needs_key": True,
+                         "configured": provider["key"] in os.environ and bool(provider["base_url"])})
+    # Ollama needs no key, so it counts as configured; whether it is running is another matter
+    backends.append({"name": "ollama",
+                     "base_url": ollama_host or os.environ.get("OLLAMA_HOST", "http://localhost:11434"),
+                     "needs_key": False,
+                     "configured": True})
+    for backend in backends:
+        backend["disabled"] = backend["name"] in disabled
+    return backends
+
+
+def provider_reachable(name, args=None):
+    """List a provider's models, the cheapest call each one has, and return how many it offers. Raises when the call fails."""
+    timeout = getattr(args, 'timeout', None) or 10
+    if name == "openai":
+        client = OpenAI(api_key=os.environ["OPENAI_API_KEY"],
+                        **client_options(args)).with_options(timeout=timeout)
+        return len(client.models.list().data)
+    if name == "claude":
+        response = requests.get(
+            "https://api.anthropic.com/v1/models",
+            headers={"x-api-key": os.environ["CLAUDE_API_KEY"],
+                     "anthropic-version": "2023-06-01"},
+            params={"limit": 1000}, timeout=timeout)
+        response.raise_for_status()
+        return len(response.json()["data"])
+    if name == "google":
+        import google.generativeai as genai
+        genai.configure(api_key=os.environ["GOOGLE_API_KEY"])
+        return len(list(genai.list_models()))
+    if name == "ollama":
+        import ollama
+        client = ollama.Client(host=getattr(args, 'remoteOllamaServer', None), timeout=timeout)
+        return len(client.list()['models'])
+    client = compatible_client(compatible_provider(name), args)
+    return len(client.with_options(timeout=timeout).models.list().data)
+
+
+def doctor(args=None):
+    """Check the config files, every provider, the pattern directories and the session store.
+
+    Prints one PASS, FAIL, WARN or SKIP line per check and returns whether nothing failed.
+    """
+    results = []
+    files = env_files(getattr(args, 'env_file', None))
+    if files:
+        results.append(("PASS", "config", ", ".join(files)))
+    else:
+        results.append(("FAIL", "config", "no .env file found. Run fabric --setup"))
+    for provider in providers(getattr(args, 'remoteOllamaServer', None), args):
+        name = f"provider {provider['name']}"
+        if provider["disabled"]:
+            results.append(("SKIP", name, "disabled"))
+            continue
+        if not provider["configured"]:
+            results.append(("SKIP", name, "no key"))
+            continue
+        try:
+            count = provider_reachable(provider["name"], args)
+            results.append(
+                ("PASS", name, f"{provider['base_url']} reachable, {count} models"))
+        except Exception as e:
+            # Ollama is used without a key, so it not running is only a problem if you use it
+            status = "WARN" if provider["name"] == "ollama" else "FAIL"
+            results.append((status, name, f"{provider['base_url']}: {e}"))
+    if not any(status == "PASS" for status, name, _ in results if name.startswith("provider ")):
+        results.append(("FAIL", "providers", "no provider could be reached"))
+    for directory in pattern_directories():
+        path = directory["path"]
+        if path == overrides_directory:
+            if os.path.isdir(path):
+                results.append(("PASS", "overrides", f"{path}, {directory['count']} patterns"))
+            else:
+                results.append(("SKIP", "overrides", f"{path} does not exist"))
+            continue
+        if not directory["count"]:
+            results.append(("FAIL", "patterns", f"no patterns in {path}. Run fabric --update"))
+            continue
+        broken = sorted(pattern for pattern in os.listdir(path)
+                        if not os.path.isfile(os.path.join(path, pattern, "system.md")))
+        if broken:
+            results.append(("WARN", "patterns", f"{path}: no system.md in {', '.join(broken)}"))
+        else:
+            results.append(("PASS", "patterns", f"{path}, {directory['count']} patterns"))
+    for registry in pattern_registries():
+        count = len(registry.patterns())
+        if count:
+            results.append(("PASS", "registry", f"{registry.url}, {count} patterns"))
+        else:
+            results.append(("WARN", "registry", f"{registry.url} lists no patterns"))
+    try:
+        from .helper import Session
+        sessions_folder = Session().sessions_folder
+        with tempfile.TemporaryFile(dir=sessions_folder):
+            pass
+        results.append(("PASS", "sessions", f"{sessions_folder} is writable"))
+    except OSError as e:
+        results.append(("FAIL", "sessions", str(e)))
+    for status, name, detail in results:
+        print(f"{status}  {name}: {detail}")
+    return not any(status == "FAIL" for status, _, _ in results)
+
+
+def compatible_providers():
+    """Return the providers reached through an OpenAI-compatible client, besides OpenAI itself.
+
+    Each is used once its key is set. The model names are what --model selects; when they
+    are None, the models are listed from the provider's API.
+    """
+    return [
+        {"name": "perplexity",
+         "label": "Perplexity",
+         "key": "PERPLEXITY_API_KEY",
+         "base_url": "https://api.perplexity.ai",
+         "models": ['llama-3-sonar-small-32k-online', 'llama-3-sonar-large-32k-online',
+                    'llama-3-sonar-small-32k-chat', 'llama-3-sonar-large-32k-chat']},
+        # Azure routes requests by deployment name, so the deployments stand in for models
+        {"name": "azure",
+         "label": "Azure OpenAI",
+         "key": "AZURE_OPENAI_API_KEY",
+         "base_url": os.environ.get("AZURE_OPENAI_ENDPOINT"),
+         "models": [deployment.strip() for deployment in os.environ.get(
+             "AZURE_OPENAI_DEPLOYMENTS", "").split(",") if deployment.strip()]},
+        {"name": "deepseek",
+         "label": "DeepSeek",
+         "key": "DEEPSEEK_API_KEY",
+         "base_url": "https://api.deepseek.com",
+         "models": ['deepseek-chat', 'deepseek-reasoner']},
+        {"name": "xai",
+         "label": "xAI",
+         "key": "XAI_API_KEY",
+         "base_url": "https://api.x.ai/v1",
+         "models": ['grok-beta']},
+        {"name": "openrouter",
+         "label": "OpenRouter",
+         "key": "OPENROUTER_API_KEY",
+         "base_url": "https://openrouter.ai/api/v1",
+         "models": None},
+    ]
+
+
+def compatible_provider(name):
+    """Return the entry of compatible_providers() with this name."""
+    return next(provider for provider in compatible_providers() if provider["name"] == name)
+
+
+def compatible_client(provider, args=None):
+    """Create the client for an entry of compatible_providers(), or None if it isn't configured."""
+    if provider["key"] not in os.environ or not provider["base_url"]:
+        return None
+    if provider["name"] == "azure":
+        from openai import AzureOpenAI
+        return AzureOpenAI(api_key=os.environ[provider["key"]], azure_endpoint=provider["base_url"],
+                           api_version=os.environ.get("OPENAI_API_VERSION", "2024-02-01"), **client_options(args))
+    return OpenAI(api_key=os.environ[provider["key"]], base_url=provider["base_url"], **client_options(args))
+
+
+def provider_extras(provider):
+    """Return the extra headers and body fields a provider's requests carry, from <PROVIDER>_EXTRA_HEADERS and <PROVIDER>_EXTRA_BODY.
+
+    Both hold a JSON object, e.g. OPENROUTER_EXTRA_HEADERS='{"X-Title": "fabric"}'. Exits on anything else.
+    """
+    extras = []
+    for kind in ["HEADERS", "BODY"]:
+        name = f"{provider.upper()}_EXTRA_{kind}"
+        try:
+            value = json.loads(os.environ.get(name) or "{}")
+        except json.JSONDecodeError as e:
+            value = e
+        if not isinstance(value, dict):
+            print(f"Error: {name} must be a JSON object", file=sys.stderr)
+            sys.exit(1)
+        extras.append(value)
+    return tuple(extras)
+
+
+def client_options(args=None):
+    """Return the keyword arguments shared by the OpenAI and Anthropic clients.
+
+    Options that weren't given are left out, so the SDK defaults apply.
+    """
+    options = {}
+    retries = getattr(args, 'retries', None)
+    if retries is not None:
+        # Both SDKs back off exponentially with jitter and honor retry-after
+        options["max_retries"] = retries
+    timeout = getattr(args, 'timeout', None)
+    if timeout:
+        options["timeout"] = timeout
+    return options
+
+
+def usable_models(provider, models, args=None):
+    """Drop a provider's models when it is disabled, and any model ALLOWED_MODELS doesn't allow."""
+    if provider in disabled_providers(args):
+        return []
+    return [model for model in models if model_allowed(model)]
+
+
+def disabled_providers(args=None):
+    """Return the names of the providers turned off with --disable-provider or DISABLED_PROVIDERS."""
+    names = os.environ.get("DISABLED_PROVIDERS", "").split(",") + \
+        (getattr(args, 'disable_provider', None) or [])
+    return {name.strip().lower() for name in names if name.strip()}
+
+
+def provider_status(provider):
+    """Describe whether a provider from providers() has the key it needs."""
+    if provider["disabled"]:
+        return "disabled"
+    if not provider["needs_key"]:
+        return "no key needed"
+    return "key configured" if provider["configured"] else "no key"
+
+
+def pattern_system_path(pattern):
+    """Return the path of the system.md fabric reads for a pattern.
+
+    A file in ~/.config/fabric/overrides/<pattern>/system.md takes precedence over the
+    downloaded pattern, so it survives --update. An alias from PATTERN_ALIASES is resolved first.
+    Patterns shipped with fabric are the last resort. A <namespace>:<pattern> name reads the
+    pattern from that registry only, or from the patterns directory with the local namespace.
+    """
+    pattern = pattern_aliases().get(pattern, pattern)
+    if pattern and ":" in pattern:
+        namespace, _, name = pattern.partition(":")
+        if namespace == "local":
+            return os.path.join(config_directory, "patterns", name, "system.md")
+        for registry in pattern_registries():
+            if registry.namespace == namespace:
+                return registry.system_path(name) or os.path.join(registry.directory, name, "system.md")
+    override = os.path.join(overrides_directory, pattern, "system.md")
+    if os.path.exists(override):
+        return override
+    path = os.path.join(config_directory, "patterns", pattern, "system.md")
+    if pattern and not os.path.exists(path):
+        for registry in pattern_registries():
+            registry_path = registry.system_path(pattern)
+            if registry_path:
+                return registry_path
+        builtin = os.path.join(builtin_patterns_directory, pattern, "system.md")
+        if os.path.exists(builtin):
+            return builtin
+    return path
+
+
+def pattern_aliases():
+    """Return the short names set in PATTERN_ALIASES, e.g. "sum=summarize,ew=extract_wisdom", mapped to their patterns."""
+    load_env_files()
+    aliases = {}
+    for entry in os.environ.get("PATTERN_ALIASES", "").split(","):
+        alias, separator, pattern = entry.partition("=")
+        if separator and alias.strip() and pattern.strip():
+            aliases[alias.strip()] = pattern.strip()
+    return aliases
+
+
+def favorites_first(patterns):
+    """Order patterns with those in FAVORITE_PATTERNS first, in the order given there, and the rest after."""
+    load_env_files()
+    favorites = [name.strip() for name in os.environ.get(
+        "FAVORITE_PATTERNS", "").split(",") if name.strip() in patterns]
+    return favorites + [pattern for pattern in patterns if pattern not in favorites]
+
+
+def pattern_user_path(pattern):
+    """Return the user.md next to the system.md fabric reads for a pattern, or None when a registry doesn't have one."""
+    system_path = pattern_system_path(pattern)
+    for registry in pattern_registries():
+        if system_path.startswith(registry.directory + os.sep):
+            # The directory name, as the pattern may have been given as an alias or with a namespace
+            return registry.file_path(os.path.basename(os.path.dirname(system_path)), "user.md")
+    return os.path.join(os.path.dirname(system_path), "user.md")
+
+
+def pattern_registries():
+    """Return the extra pattern registries listed in EXTRA_PATTERNS, in order.
+
+    EXTRA_PATTERNS is a comma-separated list of registry URLs and git+<repository>[#<ref>] sources.
+    Each can be prefixed with <namespace>= to name it; otherwise it is named after the last part of its URL.
+
+    Their patterns are used when neither the patterns directory nor the overrides have one of that name.
+    """
+    load_env_files()
+    registries = []
+    for entry in os.environ.get("EXTRA_PATTERNS", "").split(","):
+        # A URL never matches, as its scheme is followed by ":"
+        named = re.match(r"([\w-]+)=(.+)", entry.strip())
+        namespace, source = named.groups() if named else (None, entry.strip())
+        if source:
+            registries.append(pattern_registry(
+                source, namespace or default_namespace(source)))
+    return registries
+
+
+@lru_cache(maxsize=None)
+def pattern_registry(source, namespace):
+    """Return the registry for a source, created once per run so each file is revalidated only once."""
+    if source.startswith("git+"):
+        return GitPatternRegistry(source, namespace)
+    return HttpPatternRegistry(source, namespace)
+
+
+def default_namespace(source):
+    """Name a registry after the last part of its URL, e.g. "patterns" for git+https://github.com/acme/patterns.git#v1.2."""
+    url = source[len("git+"):] if source.startswith("git+") else source
+    name = url.partition("#")[0].rstrip("/").rsplit("/", 1)[-1]
+    return name[:-len(".git")] if name.endswith(".git") else name
+
+
+def pattern_sources(pattern):
+    """Return the namespaced name and location of each source that has a pattern, the one fabric uses first.
+
+    Overrides and built-in patterns are left out, as they are meant to stand in for another source.
+    """
+    sources = []
+    local = os.path.join(config_directory, "patterns")
+    if os.path.isdir(os.path.join(local, pattern)):
+        sources.append((f"local:{pattern}", local))
+    for registry in pattern_registries():
+        if pattern in registry.patterns():
+            sources.append((f"{registry.namespace}:{pattern}", registry.url))
+    return sources
+
+
+def registry_pattern_names():
+    """Return the names of the patterns the extra registries offer."""
+    return [pattern for registry in pattern_registries() for pattern in registry.patterns()]
+
+
+class GitPatternRegistry:
+    """Patterns from a git repository pinned to a branch, tag or commit, given as git+<repository URL>[#<ref>].
+
+    The revision is fetched into ~/.config/fabric/registries on first use and again by --update.
+    Its patterns are the directories with a system.md, under patterns/ if the repository has one.
+    """
+
+    def __init__(self, source, namespace=None):
+        self.namespace = namespace
+        self.url, _, ref = source[len("git+"):].partition("#")
+        self.ref = ref or "HEAD"
+        self.directory = os.path.join(
+            registries_directory, hashlib.sha256(source.encode("utf-8")).hexdigest()[:16])
+        self.pattern_names = None
+
+    def sync(self):
+        """Fetch the pinned revision and check it out, replacing whatever was there."""
+        try:
+            if not os.path.isdir(os.path.join(self.directory, ".git")):
+                os.makedirs(self.directory, exist_ok=True)
+                subprocess.run(
+                    ["git", "init", "--quiet", self.directory], check=True)
+            subprocess.run(["git", "-C", self.directory, "fetch", "--quiet",
+                           "--depth", "1", self.url, self.ref], check=True)
+            subprocess.run(["git", "-C", self.directory, "checkout", "--quiet",
+                           "--force", "--detach", "FETCH_HEAD"], check=True)
+        except (OSError, subprocess.CalledProcessError) as e:
+            print(
+                f"Warning: couldn't fetch {self.ref} of pattern registry {self.url}: {e}", file=sys.stderr)
+        self.pattern_names = None
+
+    def root(self):
+        patterns = os.path.join(self.directory, "patterns")
+        return patterns if os.path.isdir(patterns) else self.directory
+
+    def patterns(self):
+        """Return the names of the patterns in the checked-out revision, fetching it first if it never was."""
+        if self.pattern_names is None:
+            if not os.path.exists(os.path.join(self.directory, ".git", "FETCH_HEAD")):
+                self.sync()
+            root = self.root()
+            self.pattern_names = sorted(
+                name for name in os.listdir(root)
+                if not name.startswith(".") and os.path.isfile(os.path.join(root, name, "system.md")))
+        return self.pattern_names
+
+    def system_path(self, pattern):
+        """Return the system.md of a pattern, or None when the repository doesn't have it."""
+        return self.file_path(pattern, "system.md")
+
+    def file_path(self, pattern, name):
+        """Return a file of a pattern, or None when the repository doesn't have it."""
+        if pattern not in self.patterns():
+            return None
+        path = os.path.join(self.root(), pattern, name)
+        return path if os.path.exists(path) else None
+
+
+class HttpPatternRegistry:
+    """Patterns served over HTTP: <url>/index.json is a JSON list of pattern names, and <url>/<name>/system.md holds each one.
+
+    Files are cached under ~/.config/fabric/registries and revalidated with their ETag when used, so an
+    unchanged file costs a 304 and an unreachable registry falls back to the cached copy.
+    """
+
+    def __init__(self, url, namespace=None):
+        self.namespace = namespace
+        self.url = url.rstrip("/")
+        self.directory = os.path.join(
+            registries_directory, hashlib.sha256(self.url.encode("utf-8")).hexdigest()[:16])
+        self.etags_file = os.path.join(self.directory, "etags.json")
+        self.pattern_names = None
+        self.fetched = {}
+
+    def patterns(self):
+        """Return the pattern names in the registry's index."""
+        if self.pattern_names is None:
+            self.pattern_names = []
+            path = self.fetch("index.json")
+            if path:
+                try:
+                    with open(path, "r") as f:
+                        index = json.load(f)
+                    # A name is used as a path, so anything but a plain directory name is skipped
+                    self.pattern_names = [str(name) for name in index
+                                          if re.fullmatch(r"[\w-][\w.-]*", str(name))]
+                except (ValueError, TypeError):
+                    print(
+                        f"Warning: the index of pattern registry {self.url} is not a JSON list", file=sys.stderr)
+        return self.pattern_names
+
+    def system_path(self, pattern):
+        """Return the cached system.md of a pattern, or None when the registry doesn't have it."""
+        return self.file_path(pattern, "system.md")
+
+    def file_path(self, pattern, name):
+        """Return the cached copy of a file of a pattern, or None when the registry doesn't have it."""
+        if pattern not in self.patterns():
+            return None
+        return self.fetch(f"{pattern}/{name}")
+
+    def fetch(self, relative_path):
+        """Download a file of the registry unless the cached copy is still current, and return its local path."""
+        if relative_path in self.fetched:
+            return self.fetched[relative_path]
+        path = os.path.join(self.directory, *relative_path.split("/"))
+        etags = self.read_etags()
+        headers = {}
+        if os.path.exists(path) and relative_path in etags:
+            headers["If-None-Match"] = etags[relative_path]
+        try:
+            response = requests.get(
+                f"{self.url}/{relative_path}", headers=headers, timeout=10)
+            if response.status_code == 200:
+                os.makedirs(os.path.dirname(path), exist_ok=True)
+                with open(path, "wb") as f:
+                    f.write(response.content)
+                etags.pop(relative_path, None)
+                if response.headers.get("ETag"):
+                    etags[relative_path] = response.headers["ETag"]
+                self.write_etags(etags)
+            elif response.status_code == 404 and os.path.exists(path):
+                os.remove(path)
+            elif response.status_code != 304:
+                print(
+                    f"Warning: {self.url}/{relative_path} returned {response.status_code}", file=sys.stderr)
+        except requests.RequestException as e:
+            print(
+                f"Warning: pattern registry {self.url} can't be reached, using the cached copy: {e}", file=sys.stderr)
+        self.fetched[relative_path] = path if os.path.exists(path) else None
+        return self.fetched[relative_path]
+
+    def read_etags(self):
+        try:
+            with open(self.etags_file, "r") as f:
+                return json.load(f)
+        except (OSError, ValueError):
+            return {}
+
+    def write_etags(self, etags):
+        os.makedirs(self.directory, exist_ok=True)
+        with open(self.etags_file, "w") as f:
+            json.dump(etags, f, indent=2)
+
+
+def split_frontmatter(text):
+    """Split the YAML frontmatter off the top of a system.md, returning it as a dict along with the rest of the text.
+
+    Text without frontmatter, or whose frontmatter isn't a YAML mapping, is returned whole with an empty dict.
+    """
+    match = re.match(r"---[ \t]*\r?\n(.*?)\r?\n---[ \t]*(?:\r?\n|$)", text, re.DOTALL)
+    if not match:
+        return {}, text
+    import yaml
+    try:
+        metadata = yaml.safe_load(match.group(1))
+    except yaml.YAMLError:
+        metadata = None
+    if not isinstance(metadata, dict):
+        return {}, text
+    return metadata, text[match.end():]
+
+
+def pattern_metadata(pattern):
+    """Return the frontmatter of a pattern's system.md: description, model, temperature and variables. Empty when there is none."""
+    try:
+        with open(pattern_system_path(pattern), "r") as f:
+            return split_frontmatter(f.read())[0]
+    except OSError:
+        return {}
+
+
+def pattern_listing(patterns):
+    """Describe each pattern: its name, the system.md it is read from, and a description.
+
+    The description comes from the frontmatter, or else the first Markdown heading of system.md.
+    """
+    listing = []
+    for pattern in patterns:
+        path = pattern_system_path(pattern)
+        description = ""
+        try:
+            with open(path, "r") as f:
+                metadata, text = split_frontmatter(f.read())
+            description = str(metadata.get("description") or "")
+            if not description:
+                heading = re.search(r"^#+\s+(.+)$", text, re.MULTILINE)
+                description = heading.group(1).strip() if heading else ""
+        except OSError:
+            path = None
+        listing.append({"name": pattern, "source": path,
+                        "description": description})
+    return listing
+
+
+def new_pattern(name):
+    """Create ~/.config/fabric/patterns/<name>/system.md from a template and return its path, or exit if the name is taken or unusable."""
+    if not re.fullmatch(r"[\w-]+", name):
+        print(
+            f"Error: {name!r} can't be a pattern name. Use letters, digits, _ and -.", file=sys.stderr)
+        sys.exit(1)
+    directory = os.path.join(config_directory, "patterns", name)
+    if os.path.exists(directory):
+        print(f"Error: pattern {name} already exists in {directory}", file=sys.stderr)
+        sys.exit(1)
+    os.makedirs(directory)
+    path = os.path.join(directory, "system.md")
+    with open(path, "w") as f:
+        f.write("""---
+description:
+# model: gpt-4o
+# temperature: 0.3
+variables: []
+---
+# IDENTITY and PURPOSE
+
+You are
+
+# STEPS
+
+-
+
+# OUTPUT INSTRUCTIONS
+
+-
+
+# INPUT
+
+INPUT:
+""")
+    return path
+
+
+def export_patterns(path, patterns=None):
+    """Pack patterns, by default every one in the patterns directory, into a .tar.gz with one directory per pattern.
+
+    A local override's system.md is packed in place of the downloaded one. Returns the names packed.
+    """
+    patterns_directory = os.path.join(config_directory, "patterns")
+    if not patterns:
+        patterns = sorted(os.listdir(patterns_directory)) if os.path.isdir(patterns_directory) else []
+    exported = []
+    with tarfile.open(path, "w:gz") as tar:
+        for pattern in patterns:
+            system_path = pattern_system_path(pattern)
+            if not os.path.exists(system_path):
+                print(f"Error: pattern {pattern} not found", file=sys.stderr)
+                sys.exit(1)
+            name = pattern_aliases().get(pattern, pattern)
+            directory = os.path.join(patterns_directory, name)
+            if not os.path.isdir(directory):
+                directory = os.path.dirname(system_path)
+            for entry in sorted(os.listdir(directory)):
+                if entry.startswith(".") or entry.endswith("~"):
+                    continue
+                source = system_path if entry == "system.md" else os.path.join(directory, entry)
+                tar.add(source, arcname=f"{name}/{entry}")
+            exported.append(name)
+    return exported
+
+
+def import_patterns(path):
+    """Unpack a .tar.gz made by export_patterns into the patterns directory and return the names added.
+
+    Patterns that already exist are left alone. Archives with absolute paths, .. or links are refused.
+    """
+    patterns_directory = os.path.join(config_directory, "patterns")
+    imported = []
+    with tarfile.open(path, "r:gz") as tar:
+        members = tar.getmembers()
+        for member in members:
+            parts = member.name.split("/")
+            if member.name.startswith("/") or ".." in parts or not (member.isfile() or member.isdir()):
+                print(
+                    f"Error: {path} contains {member.name!r}, which can't be unpacked safely", file=sys.stderr)
+                sys.exit(1)
+        for pattern in sorted({member.name.split("/")[0] for member in members}):
+            if not re.fullmatch(r"[\w-]+", pattern):
+                print(f"Skipping {pattern!r}, which can't be a pattern name", file=sys.stderr)
+                continue
+            if os.path.exists(os.path.join(patterns_directory, pattern)):
+                print(
+                    f"Skipping {pattern}, which already exists. Delete it first to replace it.", file=sys.stderr)
+                continue
+            for member in members:
+                parts = member.name.split("/")
+                if parts[0] != pattern or not member.isfile():
+                    continue
+                target = os.path.join(patterns_directory, *parts)
+                os.makedirs(os.path.dirname(target), exist_ok=True)
+                with tar.extractfile(member) as source, open(target, "wb") as f:
+                    shutil.copyfileobj(source, f)
+            imported.append(pattern)
+    return imported
+
+
+def lint_patterns(directory=None):
+    """Check patterns for problems and return them as (pattern, problem) pairs.
+
+    With a directory, e.g. a checkout of a pattern repository, its patterns are checked (under
+    patterns/ if it has one). Without one, every pattern fabric can run is checked, including
+    names that more than one source provides.
+    """
+    problems = []
+    if directory:
+        root = os.path.join(directory, "patterns")
+        if not os.path.isdir(root):
+            root = directory
+        entries = [(name, os.path.join(root, name, "system.md")) for name in sorted(os.listdir(root))
+                   if os.path.isdir(os.path.join(root, name)) and not name.startswith(".")]
+    else:
+        entries = [(name, pattern_system_path(name)) for name in all_pattern_names()]
+        for name, _ in entries:
+            sources = [location for _, location in pattern_sources(name)]
+            if len(sources) > 1:
+                problems.append(
+                    (name, f"is provided by {', '.join(sources)}; only the first is used"))
+    for name, path in entries:
+        if not os.path.isfile(path):
+            problems.append((name, "has no system.md"))
+            continue
+        with open(path, "r") as f:
+            text = f.read()
+        metadata, body = split_frontmatter(text)
+        if re.match(r"---[ \t]*\r?\n", text) and not metadata:
+            problems.append(
+                (name, "has frontmatter that is not a closed YAML mapping"))
+        if not isinstance(metadata.get("description", ""), (str, type(None))):
+            problems.append((name, "has a description that is not text"))
+        if not isinstance(metadata.get("model", ""), (str, type(None))):
+            problems.append((name, "has a model that is not text"))
+        temperature = metadata.get("temperature", 0)
+        if isinstance(temperature, bool) or not isinstance(temperature, (int, float)):
+            problems.append((name, "has a temperature that is not a number"))
+        variables = metadata.get("variables") or []
+        if not isinstance(variables, list):
+            problems.append((name, "has variables that are not a list"))
+            variables = []
+        if not body.strip():
+            problems.append((name, "has an empty system.md"))
+        user_path = os.path.join(os.path.dirname(path), "user.md")
+        if os.path.isfile(user_path):
+            with open(user_path, "r") as f:
+                body += f.read()
+        used = set(re.findall(r"\{\{\s*(\w+)\s*\}\}", body))
+        for variable in sorted(used - {str(variable) for variable in variables} - {"input", "date"}):
+            problems.append(
+                (name, f"uses {{{{{variable}}}}}, which is not in its variables and would be sent as is"))
+    return problems
+
+
+def run_chain(args, patterns, text, context="", host=""):
+    """Send the input through every pattern of a --chain but the last, and return the input for the last one.
+
+    Each step's output is the next step's input. It is collected rather than printed, and only
+    the last step, which the caller sends, is streamed, copied, saved or added to the session.
+    """
+    for pattern in patterns[:-1]:
+        step_args = copy.copy(args)
+        step_args.copy, step_args.output, step_args.session = False, None, None
+        step_args.only_on_change, step_args.json_schema = False, None
+        standalone = Standalone(step_args, pattern)
+        output = io.StringIO()
+        with redirect_stdout(output):
+            standalone.sendMessage(text, context=context, host=host)
+        if standalone.failed or not output.getvalue().strip():
+            print(output.getvalue(), end="", file=sys.stderr)
+            print(f"Error: the chain stopped at {pattern}, which returned no output", file=sys.stderr)
+            sys.exit(1)
+        print(f"{pattern}: done", file=sys.stderr)
+        text = output.getvalue().removesuffix("\n")
+    return text
+
+
+def all_pattern_names():
+    """Return the names of every pattern fabric can run, from the patterns directory, the registries and the built-in patterns."""
+    patterns_directory = os.path.join(config_directory, "patterns")
+    local = os.listdir(patterns_directory) if os.path.isdir(patterns_directory) else []
+    return sorted(set(local) | set(registry_pattern_names()) | set(os.listdir(builtin_patterns_directory)))
+
+
+def search_patterns(query):
+    """Rank the patterns matching every word of the query, best first.
+
+    A word in the name counts most, then in the description, then each occurrence in
+    system.md, up to five.
+    """
+    words = query.lower().split()
+    matches = []
+    for entry in pattern_listing(all_pattern_names()):
+        body = ""
+        if entry["source"]:
+            with open(entry["source"], "r") as f:
+                body = f.read().lower()
+        name, description = entry["name"].lower(), entry["description"].lower()
+        if not all(word in name or word in description or word in body for word in words):
+            continue
+        score = sum(10 * (word in name) + 5 * (word in description) + min(body.count(word), 5)
+                    for word in words)
+        matches.append((score, entry))
+    matches.sort(key=lambda match: (-match[0], match[1]["name"]))
+    return [entry for _, entry in matches]
+
+
+def strategy_names():
+    """Return the names of the strategies in ~/.config/fabric/strategies."""
+    if not os.path.isdir(strategies_directory):
+        return []
+    return sorted({os.path.splitext(name)[0] for name in os.listdir(strategies_directory)
+                   if name.endswith((".json", ".md"))})
+
+
+def load_strategy(name):
+    """Return the prompt of a strategy, exiting with an error when there is no such strategy.
+
+    A strategy is either <name>.json with a "prompt", as in upstream fabric's strategies, or a plain <name>.md.
+    """
+    path = os.path.join(strategies_directory, f"{name}.json")
+    if os.path.exists(path):
+        try:
+            with open(path, "r") as f:
+                return json.load(f)["prompt"]
+        except (ValueError, KeyError, TypeError) as e:
+            print(f"Error: strategy {path} is not a JSON object with a prompt: {e}", file=sys.stderr)
+            sys.exit(1)
+    path = os.path.join(strategies_directory, f"{name}.md")
+    if os.path.exists(path):
+        with open(path, "r") as f:
+            return f.read()
+    print(f"Error: no strategy {name} in {strategies_directory}. Available: {', '.join(strategy_names()) or '(none)'}",
+          file=sys.stderr)
+    sys.exit(1)
+
+
+def pattern_directories():
+    """Return each directory fabric loads patterns from, with the number of patterns in it."""
+    directories = []
+    for directory in [overrides_directory, os.path.join(config_directory, "patterns")]:
+        count = len(os.listdir(directory)) if os.path.isdir(directory) else 0
+        directories.append({"path": directory, "count": count})
+    return directories
+
+
 def run_electron_app():
     # Step 1: Set CWD to the directory of the script
     os.chdir(os.path.dirname(os.path.realpath(__file__)))